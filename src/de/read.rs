@@ -5,23 +5,142 @@ use core_io as io;
 use error::Result;
 use serde;
 
+/// A reference to data read out of a `BincodeRead`.
+///
+/// Depending on the reader the bytes are either borrowed straight from the
+/// input and live for the deserializer's `'storage` lifetime, or they live in
+/// an internal scratch buffer and are only valid for the duration of the
+/// `&mut self` borrow that produced them. This is the same trick serde_cbor's
+/// `Read` trait uses to serve true zero-copy reads where possible and fall
+/// back to a reused scratch buffer otherwise.
+pub enum Reference<'b, 'c, T: ?Sized + 'static> {
+    /// The data is borrowed from the input and outlives the `&mut self` borrow.
+    Borrowed(&'b T),
+    /// The data lives in a scratch buffer tied to the `&mut self` borrow.
+    Copied(&'c T),
+}
+
+mod sealed {
+    /// Seals [`BincodeRead`](super::BincodeRead) so that the set of readers is
+    /// closed for semver safety. Enabling the `custom_reader` feature swaps the
+    /// per-type impls below for a blanket impl, which lets downstream crates
+    /// supply their own readers.
+    pub trait Sealed {}
+
+    #[cfg(not(feature = "custom_reader"))]
+    impl<'storage> Sealed for super::SliceReader<'storage> {}
+
+    #[cfg(not(feature = "custom_reader"))]
+    impl<R: super::io::Read> Sealed for super::IoReader<R> {}
+
+    #[cfg(feature = "custom_reader")]
+    impl<T: ?Sized> Sealed for T {}
+}
+
 /// An optional Read trait for advanced Bincode usage.
 ///
 /// It is highly recommended to use bincode with `io::Read` or `&[u8]` before
 /// implementing a custom `BincodeRead`.
-pub trait BincodeRead<'storage>: io::Read {
-    /// Forwards reading `length` bytes of a string on to the serde reader.
-    fn forward_read_str<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
-    where
-        V: serde::de::Visitor<'storage>;
+///
+/// The trait is sealed by default: only the readers shipped with bincode
+/// implement it, so adding methods is not a breaking change. Enabling the
+/// `custom_reader` feature unseals it, letting downstream crates wire bincode
+/// onto exotic sources (memory-mapped regions, ring buffers, framed
+/// transports) while still getting `visit_borrowed_*` zero-copy through
+/// [`Reference::Borrowed`]. A reader backed by a user-owned buffer can hand out
+/// borrowed slices just like `SliceReader` (requires the `custom_reader`
+/// feature):
+///
+/// ```ignore
+/// use std::io::{self, Read};
+/// use bincode::de::read::{BincodeRead, Reference};
+/// use bincode::ErrorKind;
+///
+/// struct OwnedBufReader<'storage> {
+///     slice: &'storage [u8],
+/// }
+///
+/// impl<'storage> io::Read for OwnedBufReader<'storage> {
+///     fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+///         (&mut self.slice).read(out)
+///     }
+/// }
+///
+/// impl<'storage> BincodeRead<'storage> for OwnedBufReader<'storage> {
+///     fn read_str<'a>(&'a mut self, length: usize) -> bincode::Result<Reference<'storage, 'a, str>> {
+///         let (head, tail) = self.slice.split_at(length);
+///         self.slice = tail;
+///         match std::str::from_utf8(head) {
+///             Ok(s) => Ok(Reference::Borrowed(s)),
+///             Err(e) => Err(ErrorKind::InvalidUtf8Encoding(e).into()),
+///         }
+///     }
+///
+///     fn read_bytes<'a>(&'a mut self, length: usize) -> bincode::Result<Reference<'storage, 'a, [u8]>> {
+///         let (head, tail) = self.slice.split_at(length);
+///         self.slice = tail;
+///         Ok(Reference::Borrowed(head))
+///     }
+///
+///     fn get_byte_buffer(&mut self, length: usize) -> bincode::Result<Vec<u8>> {
+///         let (head, tail) = self.slice.split_at(length);
+///         self.slice = tail;
+///         Ok(head.to_vec())
+///     }
+///
+///     fn skip_bytes(&mut self, length: usize) -> bincode::Result<()> {
+///         self.slice = &self.slice[length..];
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait BincodeRead<'storage>: io::Read + sealed::Sealed {
+    /// Reads `length` bytes and validates them as UTF-8.
+    ///
+    /// The returned [`Reference`] borrows from the input (`'storage`) when the
+    /// reader can serve it zero-copy, otherwise from a scratch buffer that only
+    /// lives as long as the `&mut self` borrow.
+    fn read_str<'a>(&'a mut self, length: usize) -> Result<Reference<'storage, 'a, str>>;
+
+    /// Reads `length` bytes.
+    ///
+    /// See [`read_str`](BincodeRead::read_str) for the meaning of the returned
+    /// [`Reference`] lifetimes.
+    fn read_bytes<'a>(&'a mut self, length: usize) -> Result<Reference<'storage, 'a, [u8]>>;
 
     /// Return the first `length` bytes of the internal byte buffer.
     fn get_byte_buffer(&mut self, length: usize) -> Result<Vec<u8>>;
 
+    /// Discards the next `length` bytes without copying them anywhere the
+    /// caller can observe.
+    ///
+    /// This is the cheap way to walk past an ignored field: `SliceReader` just
+    /// advances its cursor, and `IoReader` drains the reader through a small
+    /// fixed stack buffer rather than allocating for data it is about to throw
+    /// away.
+    fn skip_bytes(&mut self, length: usize) -> Result<()>;
+
+    /// Forwards reading `length` bytes of a string on to the serde reader.
+    fn forward_read_str<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
+    where
+        V: serde::de::Visitor<'storage>,
+    {
+        match self.read_str(length)? {
+            Reference::Borrowed(s) => visitor.visit_borrowed_str(s),
+            Reference::Copied(s) => visitor.visit_str(s),
+        }
+    }
+
     /// Forwards reading `length` bytes on to the serde reader.
     fn forward_read_bytes<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
     where
-        V: serde::de::Visitor<'storage>;
+        V: serde::de::Visitor<'storage>,
+    {
+        match self.read_bytes(length)? {
+            Reference::Borrowed(b) => visitor.visit_borrowed_bytes(b),
+            Reference::Copied(b) => visitor.visit_bytes(b),
+        }
+    }
 }
 
 /// A BincodeRead implementation for byte slices
@@ -37,6 +156,7 @@ pub struct SliceReader<'storage> {
 pub struct IoReader<R> {
     reader: R,
     temp_buffer: Vec<u8>,
+    limit: usize,
 }
 
 impl<'storage> SliceReader<'storage> {
@@ -47,11 +167,36 @@ impl<'storage> SliceReader<'storage> {
 }
 
 impl<R> IoReader<R> {
-    /// Constructs an IoReadReader
+    /// Constructs an `IoReader`.
+    ///
+    /// **The allocation ceiling is unbounded.** A single length prefix in the
+    /// stream can still make bincode reserve up to `usize::MAX` bytes before any
+    /// payload arrives, so this constructor is only appropriate for trusted
+    /// input. Use [`with_limit`](IoReader::with_limit) to cap the per-field
+    /// allocation when deserializing untrusted data.
     pub fn new(r: R) -> IoReader<R> {
         IoReader {
             reader: r,
             temp_buffer: vec![],
+            limit: ::core::usize::MAX,
+        }
+    }
+
+    /// Constructs an `IoReader` that refuses to pre-allocate more than `max`
+    /// bytes for a single field.
+    ///
+    /// A length prefix in the stream is attacker controlled, so a few header
+    /// bytes can otherwise ask bincode to reserve gigabytes before any payload
+    /// arrives. With a limit the up-front reservation is capped at `max` bytes
+    /// and the buffer only grows as data is actually read, so total allocation
+    /// tracks the bytes received (within the usual geometric-growth factor)
+    /// rather than the untrusted `length`. A truncated stream therefore fails
+    /// fast instead of allocating the full claimed size up front.
+    pub fn with_limit(r: R, max: usize) -> IoReader<R> {
+        IoReader {
+            reader: r,
+            temp_buffer: vec![],
+            limit: max,
         }
     }
 }
@@ -89,10 +234,7 @@ impl<'storage> SliceReader<'storage> {
 
 impl<'storage> BincodeRead<'storage> for SliceReader<'storage> {
     #[inline(always)]
-    fn forward_read_str<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
-    where
-        V: serde::de::Visitor<'storage>,
-    {
+    fn read_str<'a>(&'a mut self, length: usize) -> Result<Reference<'storage, 'a, str>> {
         use ErrorKind;
         if length > self.slice.len() {
             return Err(SliceReader::unexpected_eof());
@@ -102,9 +244,19 @@ impl<'storage> BincodeRead<'storage> for SliceReader<'storage> {
             Ok(s) => s,
             Err(e) => return Err(ErrorKind::InvalidUtf8Encoding(e).into()),
         };
-        let r = visitor.visit_borrowed_str(string);
         self.slice = &self.slice[length..];
-        r
+        Ok(Reference::Borrowed(string))
+    }
+
+    #[inline(always)]
+    fn read_bytes<'a>(&'a mut self, length: usize) -> Result<Reference<'storage, 'a, [u8]>> {
+        if length > self.slice.len() {
+            return Err(SliceReader::unexpected_eof());
+        }
+
+        let r = &self.slice[..length];
+        self.slice = &self.slice[length..];
+        Ok(Reference::Borrowed(r))
     }
 
     #[inline(always)]
@@ -119,17 +271,13 @@ impl<'storage> BincodeRead<'storage> for SliceReader<'storage> {
     }
 
     #[inline(always)]
-    fn forward_read_bytes<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
-    where
-        V: serde::de::Visitor<'storage>,
-    {
+    fn skip_bytes(&mut self, length: usize) -> Result<()> {
         if length > self.slice.len() {
             return Err(SliceReader::unexpected_eof());
         }
 
-        let r = visitor.visit_borrowed_bytes(&self.slice[..length]);
         self.slice = &self.slice[length..];
-        r
+        Ok(())
     }
 }
 
@@ -137,17 +285,61 @@ impl<R> IoReader<R>
 where
     R: io::Read,
 {
+    #[inline(always)]
+    fn unexpected_eof() -> Box<::ErrorKind> {
+        return Box::new(::ErrorKind::Io(
+            io::Error::new(io::ErrorKind::UnexpectedEof, ""),
+        ));
+    }
+
+    /// Fills `temp_buffer` with exactly `length` bytes.
+    ///
+    /// This walks a cursor over the vector's spare capacity instead of
+    /// `set_len`-ing over the whole uninitialized tail up front: it reserves a
+    /// window at a time, zeroes that window of spare capacity before handing it
+    /// to the reader, and commits only the bytes the reader reports filled.
+    /// Short reads are honored because the length only ever grows by the
+    /// returned count; the wrapped reader is driven through `read` only, never
+    /// `read_vectored`.
+    /// The up-front reservation is capped at `self.limit` so a hostile length
+    /// prefix cannot force a giant allocation before any payload arrives.
     fn fill_buffer(&mut self, length: usize) -> Result<()> {
-        let current_length = self.temp_buffer.len();
-        if length > current_length {
-            self.temp_buffer.reserve_exact(length - current_length);
-        }
+        let cap = ::core::cmp::max(self.limit, 1);
+
+        self.temp_buffer.clear();
+        self.temp_buffer.reserve(::core::cmp::min(length, cap));
 
-        unsafe {
-            self.temp_buffer.set_len(length);
+        while self.temp_buffer.len() < length {
+            let filled = self.temp_buffer.len();
+            if filled == self.temp_buffer.capacity() {
+                self.temp_buffer.reserve(::core::cmp::min(length - filled, cap));
+            }
+
+            // Take a read window from the spare capacity, capped at the number
+            // of bytes still wanted, and zero it: `Read::read` is free to look
+            // at its buffer, so it must never be handed uninitialized bytes.
+            let spare = self.temp_buffer.spare_capacity_mut();
+            let want = ::core::cmp::min(length - filled, spare.len());
+            for byte in spare[..want].iter_mut() {
+                *byte = ::core::mem::MaybeUninit::new(0);
+            }
+
+            // SAFETY: the first `want` bytes of the spare capacity were just
+            // initialized above.
+            let window = unsafe {
+                ::core::slice::from_raw_parts_mut(spare.as_mut_ptr() as *mut u8, want)
+            };
+            let read = self.reader.read(window)?;
+            if read == 0 {
+                return Err(IoReader::<R>::unexpected_eof());
+            }
+
+            // SAFETY: the `read` bytes starting at `filled` are now initialized.
+            unsafe {
+                self.temp_buffer.set_len(filled + read);
+            }
         }
 
-        self.reader.read_exact(&mut self.temp_buffer)?;
         Ok(())
     }
 }
@@ -156,10 +348,7 @@ impl<R> BincodeRead<'static> for IoReader<R>
 where
     R: io::Read,
 {
-    fn forward_read_str<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
-    where
-        V: serde::de::Visitor<'static>,
-    {
+    fn read_str<'a>(&'a mut self, length: usize) -> Result<Reference<'static, 'a, str>> {
         self.fill_buffer(length)?;
 
         let string = match ::core::str::from_utf8(&self.temp_buffer[..]) {
@@ -167,21 +356,30 @@ where
             Err(e) => return Err(::ErrorKind::InvalidUtf8Encoding(e).into()),
         };
 
-        let r = visitor.visit_str(string);
-        r
+        Ok(Reference::Copied(string))
+    }
+
+    fn read_bytes<'a>(&'a mut self, length: usize) -> Result<Reference<'static, 'a, [u8]>> {
+        self.fill_buffer(length)?;
+        Ok(Reference::Copied(&self.temp_buffer[..]))
     }
 
     fn get_byte_buffer(&mut self, length: usize) -> Result<Vec<u8>> {
         self.fill_buffer(length)?;
+        // The caller needs an owned `Vec`, so hand off the filled buffer
+        // directly rather than copying it. `fill_buffer` re-grows the (now
+        // empty) `temp_buffer` on the next call, which is cheaper than an
+        // allocate-and-copy on every field.
         Ok(::core::mem::replace(&mut self.temp_buffer, Vec::new()))
     }
 
-    fn forward_read_bytes<V>(&mut self, length: usize, visitor: V) -> Result<V::Value>
-    where
-        V: serde::de::Visitor<'static>,
-    {
-        self.fill_buffer(length)?;
-        let r = visitor.visit_bytes(&self.temp_buffer[..]);
-        r
+    fn skip_bytes(&mut self, mut length: usize) -> Result<()> {
+        let mut scratch = [0u8; 512];
+        while length > 0 {
+            let n = ::core::cmp::min(length, scratch.len());
+            self.reader.read_exact(&mut scratch[..n])?;
+            length -= n;
+        }
+        Ok(())
     }
 }